@@ -0,0 +1,70 @@
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+
+/// How a fenced code block's syntax highlighting colours reach the page:
+/// baked into inline `style=""` attributes (works out of the box) or as
+/// plain class names the user pairs with a stylesheet produced via
+/// `rendering::export_theme_css`.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HighlightMode {
+    Inline,
+    Css,
+}
+
+impl Default for HighlightMode {
+    fn default() -> HighlightMode {
+        HighlightMode::Inline
+    }
+}
+
+/// The `[highlight]` table in `config.toml`.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct HighlightConfig {
+    #[serde(default)]
+    pub mode: HighlightMode,
+}
+
+fn default_highlight_theme() -> String {
+    "base16-ocean-dark".to_string()
+}
+
+/// Top-level site configuration, as parsed from `config.toml`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Config {
+    pub base_url: String,
+
+    #[serde(default)]
+    pub highlight_code: bool,
+    #[serde(default = "default_highlight_theme")]
+    pub highlight_theme: String,
+    #[serde(default)]
+    pub highlight: HighlightConfig,
+
+    /// Add `target="_blank" rel="noopener"` to links that point outside
+    /// `base_url`.
+    #[serde(default)]
+    pub external_links_target_blank: bool,
+    /// Add `rel="nofollow"` to external links.
+    #[serde(default)]
+    pub external_links_no_follow: bool,
+    /// Add `rel="noreferrer"` to external links.
+    #[serde(default)]
+    pub external_links_no_referrer: bool,
+    /// CSS class added to external links, e.g. to render an icon.
+    #[serde(default)]
+    pub external_links_class: Option<String>,
+    /// Fail the build when a `#fragment` link doesn't match any heading on
+    /// the same page.
+    #[serde(default)]
+    pub check_internal_anchors: bool,
+}
+
+impl Config {
+    /// Whether code blocks should be highlighted with CSS classes instead
+    /// of inline styles, i.e. `highlight.mode = "css"`.
+    pub fn highlight_as_css_classes(&self) -> bool {
+        self.highlight.mode == HighlightMode::Css
+    }
+}
@@ -0,0 +1,61 @@
+extern crate tera;
+extern crate config;
+extern crate front_matter;
+
+use std::collections::HashMap;
+
+use tera::Tera;
+
+use config::Config;
+use front_matter::InsertAnchor;
+
+/// Everything `rendering::markdown_to_html` needs to turn a page's raw
+/// Markdown into HTML: the site-wide config (highlighting/link options)
+/// flattened onto it, plus the handful of things that vary per page.
+pub struct Context<'a> {
+    pub tera: &'a Tera,
+    pub current_page_permalink: String,
+    pub permalinks: &'a HashMap<String, String>,
+    pub insert_anchor: InsertAnchor,
+
+    pub highlight_code: bool,
+    pub highlight_theme: String,
+    pub highlight_css_classes: bool,
+
+    pub base_url: String,
+    pub external_links_target_blank: bool,
+    pub external_links_no_follow: bool,
+    pub external_links_no_referrer: bool,
+    pub external_links_class: Option<String>,
+    pub check_internal_anchors: bool,
+}
+
+impl<'a> Context<'a> {
+    pub fn new(
+        tera: &'a Tera,
+        config: &Config,
+        current_page_permalink: String,
+        permalinks: &'a HashMap<String, String>,
+        insert_anchor: InsertAnchor,
+    ) -> Context<'a> {
+        Context {
+            tera,
+            current_page_permalink,
+            permalinks,
+            insert_anchor,
+            highlight_code: config.highlight_code,
+            highlight_theme: config.highlight_theme.clone(),
+            highlight_css_classes: config.highlight_as_css_classes(),
+            base_url: config.base_url.clone(),
+            external_links_target_blank: config.external_links_target_blank,
+            external_links_no_follow: config.external_links_no_follow,
+            external_links_no_referrer: config.external_links_no_referrer,
+            external_links_class: config.external_links_class.clone(),
+            check_internal_anchors: config.check_internal_anchors,
+        }
+    }
+
+    pub fn should_insert_anchor(&self) -> bool {
+        self.insert_anchor != InsertAnchor::None
+    }
+}
@@ -0,0 +1,20 @@
+extern crate pulldown_cmark;
+extern crate slug;
+extern crate syntect;
+extern crate tera;
+extern crate url;
+extern crate regex;
+#[macro_use]
+extern crate lazy_static;
+
+extern crate context;
+extern crate errors;
+extern crate front_matter;
+extern crate highlighting;
+extern crate short_code;
+extern crate table_of_contents;
+extern crate utils;
+
+mod markdown;
+
+pub use markdown::{export_theme_css, markdown_to_html};
@@ -1,13 +1,23 @@
 use std::borrow::Cow::Owned;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 
 use pulldown_cmark as cmark;
 use self::cmark::{Parser, Event, Tag, Options, OPTION_ENABLE_TABLES, OPTION_ENABLE_FOOTNOTES};
 use slug::slugify;
 use syntect::easy::HighlightLines;
-use syntect::html::{start_coloured_html_snippet, styles_to_coloured_html, IncludeBackground};
+use syntect::html::{
+    start_coloured_html_snippet, styles_to_coloured_html, css_for_theme,
+    line_tokens_to_classed_spans, ClassStyle, IncludeBackground,
+};
+use syntect::parsing::ParseState;
 use tera::{Context as TeraContext};
+use url::Url;
+use regex::Regex;
 
-use errors::Result;
+use errors::{Error, Result};
 use utils::site::resolve_internal_link;
 use front_matter::InsertAnchor;
 use context::Context;
@@ -15,6 +25,261 @@ use highlighting::{SYNTAX_SET, THEME_SET};
 use short_code::{SHORTCODE_RE, ShortCode, parse_shortcode, render_simple_shortcode};
 use table_of_contents::{TempHeader, Header, make_table_of_contents};
 
+lazy_static! {
+    // A text span that starts with `{{` and ends with `}}` but doesn't match
+    // `SHORTCODE_RE` isn't necessarily an attempted shortcode: prose, JS
+    // object literals and math notation can incidentally look the same. We
+    // only treat it as a malformed shortcode (and error out) if it also
+    // starts like a shortcode call, i.e. `{{ name(`.
+    static ref MAYBE_SHORTCODE_RE: Regex = Regex::new(r"^\{\{\s*[A-Za-z_][A-Za-z0-9_]*\s*\(").unwrap();
+}
+
+
+/// Returns the CSS that pairs with the output of `highlight.mode = "css"`:
+/// the token colours from `theme_name` as classes instead of inline styles,
+/// so a site can ship it as its own stylesheet (and swap it for a dark-mode
+/// variant without a rebuild).
+pub fn export_theme_css(theme_name: &str) -> String {
+    css_for_theme(&THEME_SET.themes[theme_name])
+}
+
+/// A code block can either be highlighted with inline `style=""` attributes
+/// baked in from the theme (the historical default, zero extra setup) or
+/// with plain class names so the colours come from a separate stylesheet.
+enum CodeHighlighter<'a> {
+    Inline(HighlightLines<'a>),
+    Classed(ParseState),
+}
+
+/// The parsed attributes of a fenced code block info string, e.g.
+/// ```rust,linenos,hl_lines=2-4 7
+/// Only the first, comma/space-separated token is the language; everything
+/// after it is an optional annotation.
+#[derive(Clone, Default, Hash, PartialEq)]
+struct CodeBlockAttributes {
+    language: Option<String>,
+    line_numbers: bool,
+    highlighted_lines: Vec<usize>,
+}
+
+impl CodeBlockAttributes {
+    fn parse(info: &str) -> CodeBlockAttributes {
+        let mut attrs = CodeBlockAttributes::default();
+        // Whether the token we're currently looking at is still part of the
+        // space-separated list that started with the last `hl_lines=`.
+        let mut parsing_hl_lines = false;
+
+        for (i, token) in info.split(|c: char| c == ',' || c.is_whitespace()).filter(|t| !t.is_empty()).enumerate() {
+            if i == 0 {
+                attrs.language = Some(token.to_string());
+                continue;
+            }
+
+            if token == "linenos" {
+                attrs.line_numbers = true;
+                parsing_hl_lines = false;
+                continue;
+            }
+
+            if token.starts_with("hl_lines=") {
+                parsing_hl_lines = true;
+                attrs.add_highlighted_lines(&token[9..]);
+                continue;
+            }
+
+            if parsing_hl_lines {
+                attrs.add_highlighted_lines(token);
+            }
+        }
+
+        attrs
+    }
+
+    /// Adds a single `N` or inclusive `N-M` line number token to the set of
+    /// highlighted lines, ignoring anything that doesn't parse as such.
+    fn add_highlighted_lines(&mut self, range: &str) {
+        match range.find('-') {
+            Some(dash) => {
+                let start: Option<usize> = range[..dash].parse().ok();
+                let end: Option<usize> = range[dash + 1..].parse().ok();
+                if let (Some(start), Some(end)) = (start, end) {
+                    self.highlighted_lines.extend(start..=end);
+                }
+            },
+            None => {
+                if let Ok(n) = range.parse() {
+                    self.highlighted_lines.push(n);
+                }
+            },
+        }
+    }
+}
+
+/// Wraps a highlighted line's HTML with a line-number gutter and/or a
+/// highlighted-row marker, depending on what `attrs` asked for.
+fn wrap_highlighted_line(html: String, line_number: usize, attrs: &CodeBlockAttributes) -> String {
+    let html = if attrs.highlighted_lines.contains(&line_number) {
+        format!(r#"<mark class="highlighted-line">{}</mark>"#, html)
+    } else {
+        html
+    };
+
+    if attrs.line_numbers {
+        format!(r#"<span class="line-number">{}</span>{}"#, line_number, html)
+    } else {
+        html
+    }
+}
+
+/// Everything that can change a code block's highlighted output, used both
+/// as the `HIGHLIGHT_CACHE` hash key and, stored alongside the cached HTML,
+/// to confirm a hash match is an actual match rather than a collision.
+#[derive(Clone, Hash, PartialEq)]
+struct HighlightCacheKey {
+    theme_name: String,
+    as_classes: bool,
+    code: String,
+    attrs: CodeBlockAttributes,
+}
+
+thread_local! {
+    // Content-addressed cache of already-highlighted code blocks, keyed on
+    // everything that can change the output: language, annotations, theme,
+    // highlight mode and the raw code itself. Large sites tend to repeat the
+    // same snippets (shared examples, API signatures) across many pages, so
+    // this avoids re-tokenizing them with syntect on every render.
+    //
+    // Keyed on the hash of `HighlightCacheKey` for fast lookup, but each
+    // entry also stores the full key so a hash collision can't serve the
+    // wrong HTML for a different block.
+    static HIGHLIGHT_CACHE: RefCell<HashMap<u64, (HighlightCacheKey, String)>> = RefCell::new(HashMap::new());
+}
+
+/// Highlights a whole code block's raw source, line by line, against the
+/// given theme/mode/annotations.
+fn highlight_code_block(theme_name: &str, as_classes: bool, code: &str, attrs: &CodeBlockAttributes) -> String {
+    let theme = &THEME_SET.themes[theme_name];
+    SYNTAX_SET.with(|ss| {
+        let syntax = attrs.language
+            .as_ref()
+            .and_then(|lang| ss.find_syntax_by_token(lang))
+            .unwrap_or_else(|| ss.find_syntax_plain_text());
+
+        let mut highlighter = if as_classes {
+            CodeHighlighter::Classed(ParseState::new(syntax))
+        } else {
+            CodeHighlighter::Inline(HighlightLines::new(syntax, theme))
+        };
+
+        // We feed each line to the highlighter with its trailing `\n` still
+        // attached (syntect's context-popping rules match on the literal
+        // newline) and keep it in the output, which is also what gives us
+        // one rendered line per source line instead of one unbroken line.
+        let mut html = String::new();
+        let mut line_number = 0;
+        let mut remaining = code;
+        while !remaining.is_empty() {
+            line_number += 1;
+            let (line, rest) = match remaining.find('\n') {
+                Some(pos) => remaining.split_at(pos + 1),
+                None => (remaining, ""),
+            };
+            remaining = rest;
+
+            let line_html = match highlighter {
+                CodeHighlighter::Inline(ref mut h) => {
+                    let highlighted = &h.highlight(line);
+                    styles_to_coloured_html(highlighted, IncludeBackground::Yes)
+                },
+                CodeHighlighter::Classed(ref mut parse_state) => {
+                    let ops = parse_state.parse_line(line, ss);
+                    line_tokens_to_classed_spans(line, &ops[..], ClassStyle::Spaced)
+                },
+            };
+            html.push_str(&wrap_highlighted_line(line_html, line_number, attrs));
+        }
+        html
+    })
+}
+
+/// Same as `highlight_code_block` but consults `HIGHLIGHT_CACHE` first.
+fn highlight_code_block_cached(theme_name: &str, as_classes: bool, code: &str, attrs: &CodeBlockAttributes) -> String {
+    let key = HighlightCacheKey {
+        theme_name: theme_name.to_string(),
+        as_classes,
+        code: code.to_string(),
+        attrs: attrs.clone(),
+    };
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    let hash = hasher.finish();
+
+    if let Some(cached) = HIGHLIGHT_CACHE.with(|c| {
+        c.borrow().get(&hash).and_then(|(k, html)| if *k == key { Some(html.clone()) } else { None })
+    }) {
+        return cached;
+    }
+
+    let html = highlight_code_block(theme_name, as_classes, code, attrs);
+    HIGHLIGHT_CACHE.with(|c| c.borrow_mut().insert(hash, (key, html.clone())));
+    html
+}
+
+/// A link counts as external if it's an absolute `http(s)` URL whose host
+/// differs from the site's own `base_url`.
+fn is_external_link(link: &str, base_url: &str) -> bool {
+    if !(link.starts_with("http://") || link.starts_with("https://")) {
+        return false;
+    }
+
+    match (Url::parse(link), Url::parse(base_url)) {
+        (Ok(link_url), Ok(base_url)) => link_url.host_str() != base_url.host_str(),
+        _ => true,
+    }
+}
+
+/// Escapes a value so it's safe to interpolate inside a double-quoted HTML
+/// attribute. pulldown_cmark's own `Tag::Link` renderer does this for us
+/// normally; we need our own since `external_link_tag` bypasses it to add
+/// attributes (`rel`, `target`, `class`) it doesn't know about.
+fn escape_html_attribute(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Renders the opening `<a>` tag for an external link, with `rel`/`target`
+/// and an optional CSS class applied according to `context`'s config.
+/// Bypasses pulldown_cmark's own `Tag::Link` rendering since it only knows
+/// about `href`/`title`, not arbitrary attributes.
+fn external_link_tag(link: &str, title: &str, context: &Context) -> String {
+    let mut attrs = format!(r#"href="{}""#, escape_html_attribute(link));
+    if !title.is_empty() {
+        attrs.push_str(&format!(r#" title="{}""#, escape_html_attribute(title)));
+    }
+
+    let mut rel = vec![];
+    if context.external_links_no_follow {
+        rel.push("nofollow");
+    }
+    if context.external_links_no_referrer {
+        rel.push("noreferrer");
+    }
+    if context.external_links_target_blank {
+        attrs.push_str(r#" target="_blank""#);
+        rel.push("noopener");
+    }
+    if !rel.is_empty() {
+        attrs.push_str(&format!(r#" rel="{}""#, rel.join(" ")));
+    }
+    if let Some(ref class) = context.external_links_class {
+        attrs.push_str(&format!(r#" class="{}""#, escape_html_attribute(class)));
+    }
+
+    format!("<a {}>", attrs)
+}
 
 pub fn markdown_to_html(content: &str, context: &Context) -> Result<(String, Vec<Header>)> {
     // We try to be smart about highlighting code as it can be time-consuming
@@ -28,8 +293,25 @@ pub fn markdown_to_html(content: &str, context: &Context) -> Result<(String, Vec
         false
     };
     // Set while parsing
-    let mut error = None;
-    let mut highlighter: Option<HighlightLines> = None;
+    // Unresolved links, malformed/failed shortcodes, etc. We keep parsing
+    // the whole document and collect every problem (keeping each one's own
+    // error, cause chain included, rather than flattening it to a string
+    // right away) instead of bailing out on the first one, so authors can
+    // fix everything in a single pass. Each is paired with a short "where"
+    // label (occurrence count of the shortcode/link it came from) so a page
+    // with many of either can still be located without byte offsets, which
+    // this version of the markdown parser doesn't expose.
+    let mut errors: Vec<(String, Error)> = vec![];
+    // Running counts behind those "where" labels.
+    let mut shortcode_occurrence = 0;
+    let mut link_occurrence = 0;
+    // The `linenos`/`hl_lines` annotations for the code block currently
+    // being highlighted, and its accumulated raw source: we buffer the
+    // whole block so it can be looked up in `HIGHLIGHT_CACHE` as a unit
+    // instead of re-highlighting it one `Event::Text` chunk at a time.
+    let mut code_block_attrs = CodeBlockAttributes::default();
+    let mut in_highlighted_code_block = false;
+    let mut code_block_raw = String::new();
     let mut shortcode_block = None;
     // shortcodes live outside of paragraph so we need to ensure we don't close
     // a paragraph that has already been closed
@@ -42,6 +324,10 @@ pub fn markdown_to_html(content: &str, context: &Context) -> Result<(String, Vec
     // specific characters like `!` in them. We only want to insert the anchor the first time
     let mut header_already_inserted = false;
     let mut anchors: Vec<String> = vec![];
+    // `#fragment` links seen so far (paired with their link occurrence, for
+    // error messages), checked against `anchors` only once the whole
+    // document (and thus every heading) has been parsed
+    let mut linked_anchors: Vec<(usize, String)> = vec![];
 
     // the rendered html
     let mut html = String::new();
@@ -75,11 +361,12 @@ pub fn markdown_to_html(content: &str, context: &Context) -> Result<(String, Vec
     {
         let parser = Parser::new_ext(content, opts).map(|event| match event {
             Event::Text(text) => {
-                // if we are in the middle of a code block
-                if let Some(ref mut highlighter) = highlighter {
-                    let highlighted = &highlighter.highlight(&text);
-                    let html = styles_to_coloured_html(highlighted, IncludeBackground::Yes);
-                    return Event::Html(Owned(html));
+                // if we are in the middle of a code block, buffer its raw
+                // source; it gets highlighted as a whole in Event::End so it
+                // can be cached and looked up by content.
+                if in_highlighted_code_block {
+                    code_block_raw.push_str(&text);
+                    return Event::Html(Owned("".to_string()));
                 }
 
                 if in_code_block {
@@ -87,22 +374,35 @@ pub fn markdown_to_html(content: &str, context: &Context) -> Result<(String, Vec
                 }
 
                 // Shortcode without body
-                if shortcode_block.is_none() && text.starts_with("{{") && text.ends_with("}}") && SHORTCODE_RE.is_match(&text) {
-                    let (name, args) = parse_shortcode(&text);
-                    added_shortcode = true;
-                    match render_simple_shortcode(context.tera, &name, &args) {
-                        Ok(s) => return Event::Html(Owned(format!("</p>{}", s))),
-                        Err(e) => {
-                            error = Some(e);
-                            return Event::Html(Owned("".to_string()));
+                if shortcode_block.is_none() && text.starts_with("{{") && text.ends_with("}}") {
+                    if SHORTCODE_RE.is_match(&text) {
+                        shortcode_occurrence += 1;
+                        let (name, args) = parse_shortcode(&text);
+                        added_shortcode = true;
+                        match render_simple_shortcode(context.tera, &name, &args) {
+                            Ok(s) => return Event::Html(Owned(format!("</p>{}", s))),
+                            Err(e) => {
+                                errors.push((format!("shortcode call #{} (`{}`)", shortcode_occurrence, name), e));
+                                return Event::Html(Owned("".to_string()));
+                            }
                         }
+                    } else if MAYBE_SHORTCODE_RE.is_match(&text) {
+                        shortcode_occurrence += 1;
+                        errors.push((
+                            format!("shortcode call #{}", shortcode_occurrence),
+                            format!("Shortcode `{}` is malformed.", text).into(),
+                        ));
+                        // fall through and render as plain text below
                     }
-                    // non-matching will be returned normally below
+                    // anything else merely has the same `{{ ... }}` shape as
+                    // a shortcode (prose, JS, math notation...); leave it as
+                    // plain text, same as the baseline behaviour
                 }
 
                 // Shortcode with a body
                 if shortcode_block.is_none() && text.starts_with("{%") && text.ends_with("%}") {
                     if SHORTCODE_RE.is_match(&text) {
+                        shortcode_occurrence += 1;
                         let (name, args) = parse_shortcode(&text);
                         shortcode_block = Some(ShortCode::new(&name, args));
                     }
@@ -119,7 +419,7 @@ pub fn markdown_to_html(content: &str, context: &Context) -> Result<(String, Vec
                             match shortcode.render(context.tera) {
                                 Ok(s) => return Event::Html(Owned(format!("</p>{}", s))),
                                 Err(e) => {
-                                    error = Some(e);
+                                    errors.push((format!("shortcode call #{}", shortcode_occurrence), e));
                                     return Event::Html(Owned("".to_string()));
                                 }
                             }
@@ -167,16 +467,14 @@ pub fn markdown_to_html(content: &str, context: &Context) -> Result<(String, Vec
                 if !should_highlight {
                     return Event::Html(Owned("<pre><code>".to_owned()));
                 }
-                let theme = &THEME_SET.themes[&context.highlight_theme];
-                highlighter = SYNTAX_SET.with(|ss| {
-                    let syntax = info
-                        .split(' ')
-                        .next()
-                        .and_then(|lang| ss.find_syntax_by_token(lang))
-                        .unwrap_or_else(|| ss.find_syntax_plain_text());
-                    Some(HighlightLines::new(syntax, theme))
-                });
-                let snippet = start_coloured_html_snippet(theme);
+                code_block_attrs = CodeBlockAttributes::parse(info);
+                in_highlighted_code_block = true;
+                code_block_raw.clear();
+                let snippet = if context.highlight_css_classes {
+                    "<pre><code>".to_owned()
+                } else {
+                    start_coloured_html_snippet(&THEME_SET.themes[&context.highlight_theme])
+                };
                 Event::Html(Owned(snippet))
             },
             Event::End(Tag::CodeBlock(_)) => {
@@ -184,27 +482,61 @@ pub fn markdown_to_html(content: &str, context: &Context) -> Result<(String, Vec
                 if !should_highlight{
                     return Event::Html(Owned("</code></pre>\n".to_owned()))
                 }
-                // reset highlight and close the code block
-                highlighter = None;
-                Event::Html(Owned("</pre>".to_owned()))
+                // highlight (or fetch from cache) the whole buffered block,
+                // then close it
+                in_highlighted_code_block = false;
+                let highlighted = highlight_code_block_cached(
+                    &context.highlight_theme,
+                    context.highlight_css_classes,
+                    &code_block_raw,
+                    &code_block_attrs,
+                );
+                code_block_attrs = CodeBlockAttributes::default();
+                code_block_raw.clear();
+                let closing_tag = if context.highlight_css_classes {
+                    "</code></pre>"
+                } else {
+                    "</pre>"
+                };
+                Event::Html(Owned(format!("{}{}", highlighted, closing_tag)))
             },
             // Need to handle relative links
             Event::Start(Tag::Link(ref link, ref title)) => {
                 if in_header {
                     return Event::Html(Owned("".to_owned()));
                 }
+                link_occurrence += 1;
                 if link.starts_with("./") {
                     match resolve_internal_link(link, context.permalinks) {
                         Ok(url) => {
                             return Event::Start(Tag::Link(Owned(url), title.clone()));
                         },
                         Err(_) => {
-                            error = Some(format!("Relative link {} not found.", link).into());
+                            errors.push((
+                                format!("link #{}", link_occurrence),
+                                format!("Relative link {} not found.", link).into(),
+                            ));
                             return Event::Html(Owned("".to_string()));
                         }
                     };
                 }
 
+                // Opt-in: flag links to an anchor on the current page that
+                // doesn't match any heading. Headings later in the page
+                // haven't been collected into `anchors` yet at this point,
+                // so we only record the link and check it once parsing the
+                // whole document is done.
+                if context.check_internal_anchors && link.starts_with('#') {
+                    let anchor = &link[1..];
+                    if !anchor.is_empty() {
+                        linked_anchors.push((link_occurrence, anchor.to_string()));
+                    }
+                }
+
+                if is_external_link(link, &context.base_url) {
+                    return Event::Html(Owned(external_link_tag(link, title, context)));
+                }
+
                 Event::Start(Tag::Link(link.clone(), title.clone()))
             },
             Event::End(Tag::Link(_, _)) => {
@@ -257,8 +589,98 @@ pub fn markdown_to_html(content: &str, context: &Context) -> Result<(String, Vec
         cmark::html::push_html(&mut html, parser);
     }
 
-    match error {
-        Some(e) => Err(e),
-        None => Ok((html.replace("<p></p>", ""), make_table_of_contents(&headers))),
+    for (link_number, anchor) in &linked_anchors {
+        if !anchors.contains(anchor) {
+            errors.push((
+                format!("link #{}", link_number),
+                format!("Internal anchor #{} not found in this page.", anchor).into(),
+            ));
+        }
+    }
+
+    if !errors.is_empty() {
+        // The `Result<(String, Vec<Header>)>` we return only has room for a
+        // single error, so this is the one place we collapse the collected
+        // errors down to a message — using each one's `Debug` (its full
+        // cause chain), not just its top-level `Display`, so failures like
+        // a Tera render error don't lose the reason it actually failed. Each
+        // is prefixed with its "where" label so a page with many shortcodes
+        // or links can still be located in one pass.
+        let messages: Vec<String> = errors.iter().map(|(where_, e)| format!("{}: {:?}", where_, e)).collect();
+        return Err(messages.join("\n\n").into());
+    }
+
+    Ok((html.replace("<p></p>", ""), make_table_of_contents(&headers)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{escape_html_attribute, highlight_code_block, is_external_link, CodeBlockAttributes};
+
+    #[test]
+    fn parses_language_and_annotations() {
+        let attrs = CodeBlockAttributes::parse("rust,linenos,hl_lines=2-4 7");
+        assert_eq!(attrs.language, Some("rust".to_string()));
+        assert!(attrs.line_numbers);
+        assert_eq!(attrs.highlighted_lines, vec![2, 3, 4, 7]);
+    }
+
+    #[test]
+    fn parses_language_only() {
+        let attrs = CodeBlockAttributes::parse("python");
+        assert_eq!(attrs.language, Some("python".to_string()));
+        assert!(!attrs.line_numbers);
+        assert!(attrs.highlighted_lines.is_empty());
+    }
+
+    #[test]
+    fn ignores_unparseable_highlighted_lines() {
+        let mut attrs = CodeBlockAttributes::default();
+        attrs.add_highlighted_lines("not-a-number");
+        attrs.add_highlighted_lines("a-b");
+        assert!(attrs.highlighted_lines.is_empty());
+    }
+
+    #[test]
+    fn adds_single_and_range_highlighted_lines() {
+        let mut attrs = CodeBlockAttributes::default();
+        attrs.add_highlighted_lines("3");
+        attrs.add_highlighted_lines("5-7");
+        assert_eq!(attrs.highlighted_lines, vec![3, 5, 6, 7]);
+    }
+
+    #[test]
+    fn same_host_link_is_not_external() {
+        assert!(!is_external_link("https://example.com/about", "https://example.com"));
+    }
+
+    #[test]
+    fn different_host_link_is_external() {
+        assert!(is_external_link("https://other.com/about", "https://example.com"));
+    }
+
+    #[test]
+    fn relative_link_is_not_external() {
+        assert!(!is_external_link("./about.md", "https://example.com"));
+        assert!(!is_external_link("#anchor", "https://example.com"));
+    }
+
+    #[test]
+    fn escapes_html_attribute_special_characters() {
+        assert_eq!(
+            escape_html_attribute(r#"<a href="x">&"#),
+            "&lt;a href=&quot;x&quot;&gt;&amp;"
+        );
+    }
+
+    #[test]
+    fn highlights_multiline_code_one_line_break_per_source_line() {
+        let attrs = CodeBlockAttributes::parse("rust,linenos");
+        let html = highlight_code_block("base16-ocean-dark", true, "fn main() {\n    1;\n}", &attrs);
+
+        // Three source lines means two embedded line breaks, not the whole
+        // block concatenated onto a single unbroken line.
+        assert_eq!(html.matches('\n').count(), 2);
+        assert_eq!(html.matches(r#"<span class="line-number">"#).count(), 3);
     }
 }
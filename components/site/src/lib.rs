@@ -0,0 +1,23 @@
+extern crate config;
+extern crate rendering;
+
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+
+use config::Config;
+use rendering::export_theme_css;
+
+/// Writes `highlight.css` to the output directory when the site is
+/// configured to highlight code blocks with CSS classes
+/// (`highlight.mode = "css"`), so a theme only has to `<link>` it to get
+/// the configured theme's colours.
+pub fn write_highlight_stylesheet(output_dir: &Path, config: &Config) -> io::Result<()> {
+    if !config.highlight_as_css_classes() {
+        return Ok(());
+    }
+
+    let css = export_theme_css(&config.highlight_theme);
+    File::create(output_dir.join("highlight.css"))?.write_all(css.as_bytes())
+}